@@ -1,18 +1,46 @@
 use std::{
     borrow::Cow,
+    future::Future,
     io::{Read, Write},
-    net::{SocketAddr, TcpListener, TcpStream},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpListener, TcpStream},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{Context, Poll, Waker},
     thread,
+    time::Duration,
 };
 
 use tauri::{
     plugin::{Builder, TauriPlugin},
-    Runtime,
+    Manager, RunEvent, Runtime, WindowEvent,
 };
 
 const EXIT: [u8; 4] = [1, 3, 3, 7];
+/// Requests larger than this are rejected before the headers are fully parsed.
+const MAX_REQUEST_SIZE: usize = 16 * 1024;
+/// Environment variable to drive the server with a pre-captured URL, for automated tests and headless CI.
+///
+/// If it is set once the server starts accepting connections, the handler is executed with its value right away
+/// and the server shuts down without waiting for a real redirect. `oauth://started` and the returned port work as usual.
+/// The value is validated like a real redirect (see [`OauthConfig::expected_issuer`]). If it is rejected,
+/// the server waits for a real redirect instead.
+///
+/// Requires the `test-redirect` feature. Only enable it for test builds, never in production.
+#[cfg(feature = "test-redirect")]
+pub const TEST_REDIRECT_ENV: &str = "TAURI_OAUTH_TEST_REDIRECT";
+const DEFAULT_ALLOWED_METHODS: [&str; 3] = ["GET", "POST", "OPTIONS"];
+/// Headers that are only added by proxies. Requests containing any of them are logged, because a system proxy
+/// intercepting loopback requests (common in corporate environments) is a hard to spot cause of broken redirects.
+/// The headers themselves are ignored.
+const PROXY_HEADERS: [&str; 4] = ["Via", "Forwarded", "X-Forwarded-For", "Proxy-Connection"];
+const DEFAULT_ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+const DEFAULT_RESPONSE: &str = "<html><body>Please return to the app.</body></html>";
 
 /// Starts the localhost (using 127.0.0.1) server. Returns the port its listening on.
+/// Use [`start_with_config`] with [`OauthConfig::addr_family`] to bind to `[::1]` instead.
 ///
 /// Because of the unprotected localhost port, you _must_ verify the URL in the handler function.
 ///
@@ -28,14 +56,25 @@ const EXIT: [u8; 4] = [1, 3, 3, 7];
 ///
 /// The seperate server thread can panic if its unable to send the html response to the client. This may change after more real world testing.
 pub fn start<F: FnMut(String) + Send + 'static>(handler: F) -> Result<u16, std::io::Error> {
-    start_with_config(OauthConfig::default(), handler)
+    start_with_config(OauthConfig::new(), handler)
 }
 
 /// The optional server config.
+///
+/// New fields may be added in any release, so the struct can't be constructed with a struct literal.
+/// Start with [`OauthConfig::new`] (or [`Default::default`]) and set the fields you need instead:
+///
+/// ```
+/// let mut config = tauri_plugin_oauth::OauthConfig::new();
+/// config.ports = Some(vec![8000, 8001]);
+/// ```
 #[derive(Default, serde::Deserialize)]
+#[non_exhaustive]
 pub struct OauthConfig {
     /// An array of hard-coded ports the server should try to bind to.
     /// This should only be used if your oauth provider does not accept wildcard localhost addresses.
+    /// The ports are tried in order. A `0` asks the system for a free port, but only after all other ports failed,
+    /// regardless of its position in the array.
     ///
     /// Default: Asks the system for a free port.
     pub ports: Option<Vec<u16>>,
@@ -44,90 +83,904 @@ pub struct OauthConfig {
     ///
     /// Default: `"<html><body>Please return to the app.</body></html>"`.
     pub response: Option<Cow<'static, str>>,
+    /// Optional `SO_LINGER` value applied to every accepted connection.
+    /// `Some(Duration::ZERO)` resets the connection on close instead of leaving it in `TIME_WAIT`,
+    /// which frees fixed ports faster if you restart the server on the same port quickly.
+    /// Note that a reset discards any response data the OS hasn't sent yet, and non-zero values
+    /// make closing the connection block until the data is sent or the timeout elapsed.
+    ///
+    /// Default: `None` (OS behavior).
+    #[serde(skip)]
+    pub linger: Option<Duration>,
+    /// Optional closure to rewrite the captured URL before it's passed to the handler,
+    /// for example to fix the encoding of quirky providers or to strip tracking parameters.
+    /// It runs after the URL was validated (see `expected_issuer`) and right before the handler,
+    /// so the plugin's URL parsing sees the rewritten URL.
+    ///
+    /// Default: `None` (the URL is passed on unchanged).
+    #[serde(skip)]
+    pub transform_url: Option<Box<dyn Fn(String) -> String + Send + Sync>>,
+    /// Whether to set `TCP_NODELAY` on accepted connections so the response is flushed immediately
+    /// instead of being delayed by Nagle's algorithm.
+    ///
+    /// Default: `true`.
+    pub nodelay: Option<bool>,
+    /// Optional URL to open with the system's default handler after a URL was captured and the handler returned,
+    /// for example a deep link back into your app or a web dashboard.
+    /// This is independent of the html `response` which is shown in the browser tab that was redirected to the server.
+    /// `file:`, `javascript:` and `data:` URLs are rejected.
+    ///
+    /// This can only be set from Rust. The `start` command ignores it, so the webview can't make the app
+    /// open arbitrary URLs past the shell `open` scope.
+    ///
+    /// Requires the `open` feature.
+    ///
+    /// Default: `None`.
+    #[cfg(feature = "open")]
+    #[serde(skip)]
+    pub on_success_open_url: Option<String>,
+    /// Optional static html string send to clients that connected while the server was shutting down,
+    /// for example a second redirect that raced with the first one or with [`cancel`].
+    ///
+    /// Default: `"<html><body>This login session has ended, please retry.</body></html>"`.
+    pub shutdown_response: Option<Cow<'static, str>>,
+    /// The HTTP methods the server responds to. Requests using other methods get a `405 Method Not Allowed`
+    /// response and never reach the handler.
+    ///
+    /// Default: `["GET", "POST", "OPTIONS"]`.
+    pub allowed_methods: Option<Vec<String>>,
+    /// Optional value of the `Cache-Control` header sent with the html responses,
+    /// for example `"no-store"` to make sure the page isn't rendered from cache on back-navigation.
+    /// Must not contain line breaks.
+    ///
+    /// Default: `None` (no `Cache-Control` header).
+    pub cache_control: Option<String>,
+    /// Whether rejected requests (e.g. malformed requests or disallowed methods) get a short reason in the response body.
+    /// This helps during development but discloses details about the server's expectations, so keep it off in production.
+    ///
+    /// Default: `false`.
+    #[serde(default)]
+    pub verbose_rejections: bool,
+    /// The address family of the loopback address the server binds to.
+    /// Use [`AddrFamily::V6`] if your oauth provider only redirects to `[::1]`.
+    ///
+    /// Default: [`AddrFamily::V4`] (`127.0.0.1`).
+    pub addr_family: Option<AddrFamily>,
+    /// How long the server waits before accepting connections again after accepting one failed,
+    /// for example because the process ran out of file descriptors.
+    ///
+    /// Default: 100 milliseconds.
+    #[serde(skip)]
+    pub accept_backoff: Option<Duration>,
+    /// Optional name to identify the server in debuggers and profilers.
+    /// The server thread is called `oauth-<name>-<port>`, or `oauth-<port>` if this is not set.
+    /// Must not contain control characters.
+    ///
+    /// Default: `None`.
+    pub name: Option<String>,
+    /// Optional override of the response to `OPTIONS` requests, for example to add CORS headers.
+    /// Only applies if `OPTIONS` is in `allowed_methods`.
+    ///
+    /// Default: `204 No Content` with an `Allow` header.
+    pub options_response: Option<MethodResponse>,
+    /// Optional override of the response to `HEAD` requests. Only applies if `HEAD` is in `allowed_methods`.
+    /// The `body` is ignored, the response never has one.
+    ///
+    /// Default: The status and headers of the html response.
+    pub head_response: Option<MethodResponse>,
+    /// Whether to trust the `X-Forwarded-Proto` and `X-Forwarded-Host` headers, for development setups that
+    /// intentionally put the server behind a proxy or tunnel. If both are present, the redirect page sends the
+    /// captured URL back through the forwarded scheme and host instead of the loopback address.
+    ///
+    /// Anyone who can reach the proxy can set these headers, so only enable this if you control the proxy
+    /// and it overwrites them. The values are validated, but never enable this in production.
+    ///
+    /// Default: `false` (the headers are ignored).
+    #[serde(default)]
+    pub trust_forwarded_headers: bool,
+    /// The issuer identifier of your oauth provider. If set, the `iss` parameter of the authorization response
+    /// ([RFC 9207](https://www.rfc-editor.org/rfc/rfc9207)) must match it exactly to mitigate mix-up attacks.
+    /// Responses with a different or missing `iss` get a `400 Bad Request`, never reach the handler,
+    /// and the server keeps waiting for a valid redirect.
+    /// Only set this if your provider sends the parameter (`authorization_response_iss_parameter_supported`).
+    ///
+    /// Default: `None` (the `iss` parameter is not checked).
+    pub expected_issuer: Option<String>,
+    /// Optional closure executed when a response was rejected because of `expected_issuer`.
+    /// It receives the `iss` parameter of the rejected response, if any.
+    ///
+    /// Default: `None`.
+    #[serde(skip)]
+    pub on_invalid_issuer: Option<Box<dyn Fn(Option<String>) + Send + Sync>>,
 }
 
-/// Starts the localhost (using 127.0.0.1) server. Returns the port its listening on.
+/// A response override for a specific HTTP method, see [`OauthConfig::options_response`] and [`OauthConfig::head_response`].
+///
+/// The `headers` are sent after the built-in headers. A header with the same name as a built-in one (case-insensitive),
+/// e.g. `Allow`, replaces it. `Content-Length` and `Transfer-Encoding` can't be set, the server frames the response itself.
+/// Header names must be valid HTTP tokens, and none of the values may contain line breaks.
+#[derive(Clone, Default, serde::Deserialize)]
+#[non_exhaustive]
+pub struct MethodResponse {
+    /// The status code and reason, e.g. `"200 OK"`.
+    ///
+    /// Default: The built-in status of the method.
+    pub status: Option<String>,
+    /// Additional headers as name-value pairs.
+    ///
+    /// Default: No additional headers.
+    pub headers: Option<Vec<(String, String)>>,
+    /// Optional body. It is ignored for `1xx`, `204` and `304` statuses, which never have a body.
+    ///
+    /// Default: No body.
+    pub body: Option<String>,
+}
+
+impl MethodResponse {
+    fn status_or<'a>(&'a self, default: &'a str) -> &'a str {
+        self.status.as_deref().unwrap_or(default)
+    }
+
+    fn extend_headers<'a>(&'a self, headers: &mut Vec<(&'a str, &'a str)>) {
+        let built_in = headers.len();
+        for (name, value) in self.headers.iter().flatten() {
+            match headers[..built_in]
+                .iter_mut()
+                .find(|(built_in_name, _)| built_in_name.eq_ignore_ascii_case(name))
+            {
+                Some(header) => *header = (name, value),
+                None => headers.push((name, value)),
+            }
+        }
+    }
+
+    fn validate(&self, field: &str) -> Result<(), std::io::Error> {
+        if let Some(status) = &self.status {
+            let bytes = status.as_bytes();
+            if bytes.len() < 5 || !bytes[..3].iter().all(u8::is_ascii_digit) || bytes[3] != b' ' {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "Invalid `{}`: the status must be a three-digit code and a reason, e.g. `200 OK`",
+                        field
+                    ),
+                ));
+            }
+        }
+        for (name, _) in self.headers.iter().flatten() {
+            if name.is_empty() || !name.bytes().all(is_token_byte) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("Invalid `{}`: `{}` is not a valid header name", field, name),
+                ));
+            }
+            if name.eq_ignore_ascii_case("Content-Length")
+                || name.eq_ignore_ascii_case("Transfer-Encoding")
+            {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!(
+                        "Invalid `{}`: the `{}` header is set by the server",
+                        field, name
+                    ),
+                ));
+            }
+        }
+        let status = self.status.iter().map(String::as_str);
+        let headers = self
+            .headers
+            .iter()
+            .flatten()
+            .flat_map(|(name, value)| [name.as_str(), value.as_str()]);
+        if status.chain(headers).any(|v| v.contains(['\r', '\n'])) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "Invalid `{}`: the status and headers must not contain line breaks",
+                    field
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// Whether the byte is allowed in an HTTP token, e.g. a header name.
+fn is_token_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&byte)
+}
+
+/// The address family of the loopback address the server binds to, see [`OauthConfig::addr_family`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[non_exhaustive]
+pub enum AddrFamily {
+    /// Binds to `127.0.0.1`.
+    V4,
+    /// Binds to `[::1]`.
+    V6,
+}
+
+impl AddrFamily {
+    fn loopback(self) -> IpAddr {
+        match self {
+            Self::V4 => IpAddr::V4(Ipv4Addr::LOCALHOST),
+            Self::V6 => IpAddr::V6(Ipv6Addr::LOCALHOST),
+        }
+    }
+
+    fn loopback_host(self) -> &'static str {
+        match self {
+            Self::V4 => "127.0.0.1",
+            Self::V6 => "[::1]",
+        }
+    }
+}
+
+impl OauthConfig {
+    /// Creates a config with every option set to its default.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn html_headers(&self) -> Vec<(&str, &str)> {
+        self.cache_control
+            .as_deref()
+            .map(|value| ("Cache-Control", value))
+            .into_iter()
+            .collect()
+    }
+}
+
+/// Returns the html string the server responds with after being redirected, before the redirect script is injected.
+///
+/// The precedence is:
+/// 1. `config.response` if set.
+/// 2. `conf_json_fallback` if set. The plugin passes the `oauth` value in `plugins` of tauri.conf.json here.
+/// 3. The library's default, see [`OauthConfig::response`].
+#[must_use]
+pub fn effective_response<'a>(
+    config: &'a OauthConfig,
+    conf_json_fallback: Option<&'a str>,
+) -> Cow<'a, str> {
+    match (&config.response, conf_json_fallback) {
+        (Some(response), _) => Cow::Borrowed(response),
+        (None, Some(fallback)) => Cow::Borrowed(fallback),
+        (None, None) => Cow::Borrowed(DEFAULT_RESPONSE),
+    }
+}
+
+/// Starts the localhost (using `127.0.0.1` or `[::1]`, see [`OauthConfig::addr_family`]) server.
+/// Returns the port its listening on.
 ///
 /// Because of the unprotected localhost port, you _must_ verify the URL in the handler function.
 ///
 /// # Arguments
 ///
-/// * `config` - Configuration the server should use, see [`OauthConfig.]
+/// * `config` - Configuration the server should use, see [`OauthConfig`]
 /// * `handler` - Closure which will be executed on a successful connection. It receives the full URL as a String.
 ///
 /// # Errors
 ///
 /// - Returns `std::io::Error` if the server creation fails.
+/// - Returns `std::io::Error` wrapping [`TooManyServers`] if too many servers are running, see [`set_max_servers`].
 ///
 /// # Panics
 ///
 /// The seperate server thread can panic if its unable to send the html response to the client. This may change after more real world testing.
 pub fn start_with_config<F: FnMut(String) + Send + 'static>(
     config: OauthConfig,
-    mut handler: F,
+    handler: F,
 ) -> Result<u16, std::io::Error> {
-    let listener = match config.ports {
-        Some(ports) => TcpListener::bind(
-            ports
-                .iter()
-                .map(|p| SocketAddr::from(([127, 0, 0, 1], *p)))
-                .collect::<Vec<SocketAddr>>()
-                .as_slice(),
-        ),
-        None => TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0))),
-    }?;
-
-    let port = listener.local_addr()?.port();
-
-    thread::spawn(move || {
-        for conn in listener.incoming() {
-            match conn {
-                Ok(conn) => {
-                    if let Some(url) = handle_connection(conn, config.response.as_deref(), port) {
-                        // Using an empty string to communicate that a shutdown was requested.
-                        if !url.is_empty() {
-                            handler(url);
-                        }
-                        // TODO: Check if exiting here is always okay.
-                        break;
+    reserve(config)?.accept(handler)
+}
+
+/// Binds the localhost (using `127.0.0.1` or `[::1]`, see [`OauthConfig::addr_family`]) server without accepting connections yet.
+///
+/// Use this if you need the port early, for example to build the redirect URL,
+/// but don't want to handle the redirect before your app is ready for it.
+/// Call [`Reserved::accept`] to start the server.
+///
+/// Connections made before [`Reserved::accept`] is called are queued by the OS and handled once it is called,
+/// they are not lost. Dropping the [`Reserved`] value without calling [`Reserved::accept`] frees the port again.
+///
+/// # Arguments
+///
+/// * `config` - Configuration the server should use, see [`OauthConfig`]
+///
+/// # Errors
+///
+/// - Returns `std::io::Error` if the server creation fails.
+/// - Returns `std::io::Error` of kind `InvalidInput` if the config is invalid.
+/// - Returns `std::io::Error` of kind `Other` wrapping [`TooManyServers`] if too many servers are running,
+///   see [`set_max_servers`].
+pub fn reserve(config: OauthConfig) -> Result<Reserved, std::io::Error> {
+    #[cfg(feature = "open")]
+    if let Some(open_url) = &config.on_success_open_url {
+        validate_open_url(open_url)?;
+    }
+    if let Some(cache_control) = &config.cache_control {
+        if cache_control.contains(['\r', '\n']) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Invalid `cache_control`: the value must not contain line breaks",
+            ));
+        }
+    }
+    if let Some(name) = &config.name {
+        if name.contains(char::is_control) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "Invalid `name`: the value must not contain control characters",
+            ));
+        }
+    }
+    if let Some(options_response) = &config.options_response {
+        options_response.validate("options_response")?;
+    }
+    if let Some(head_response) = &config.head_response {
+        head_response.validate("head_response")?;
+    }
+
+    let slot = ServerSlot::acquire()?;
+
+    let family = config.addr_family.unwrap_or(AddrFamily::V4);
+    let candidates: Vec<u16> = match &config.ports {
+        Some(ports) => ports
+            .iter()
+            .filter(|p| **p != 0)
+            .chain(ports.iter().find(|p| **p == 0))
+            .copied()
+            .collect(),
+        None => vec![0],
+    };
+
+    let mut attempted = Vec::with_capacity(candidates.len());
+    let mut last_err = None;
+    let mut listener = None;
+    for candidate in candidates {
+        attempted.push(candidate);
+        match TcpListener::bind(SocketAddr::new(family.loopback(), candidate)) {
+            Ok(l) => {
+                listener = Some(l);
+                break;
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    let listener = match listener {
+        Some(listener) => listener,
+        None => {
+            return Err(last_err.unwrap_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "Invalid `ports`: the array must not be empty",
+                )
+            }))
+        }
+    };
+
+    let port = match listener.local_addr() {
+        Ok(addr) => addr.port(),
+        Err(err) => {
+            // Close the listener before returning so the bound port isn't held any longer than necessary.
+            drop(listener);
+            return Err(std::io::Error::new(
+                err.kind(),
+                format!(
+                    "Bound the server but couldn't read its local address: {}",
+                    err
+                ),
+            ));
+        }
+    };
+
+    let strategy = match attempted.as_slice() {
+        [0] => BindStrategy::Ephemeral,
+        [.., 0] => BindStrategy::EphemeralFallback,
+        _ => BindStrategy::Ports,
+    };
+
+    Ok(Reserved {
+        listener,
+        config,
+        report: BindReport {
+            selected_port: port,
+            family,
+            attempted,
+            strategy,
+        },
+        slot,
+    })
+}
+
+/// Describes how the server's port was chosen, see [`Reserved::bind_report`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BindReport {
+    /// The port the server is bound to.
+    pub selected_port: u16,
+    /// The address family of the loopback address the server is bound to.
+    pub family: AddrFamily,
+    /// The ports that were tried, in order. The last one is the one that succeeded, `0` stands for a port chosen by the system.
+    pub attempted: Vec<u16>,
+    /// Which of the configured ports resolved the binding.
+    pub strategy: BindStrategy,
+}
+
+/// How the server's port was chosen, see [`BindReport`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BindStrategy {
+    /// No explicit `ports` were configured (`None` or only `0`), the system chose a free port.
+    Ephemeral,
+    /// One of the explicitly configured `ports` was used.
+    Ports,
+    /// All explicitly configured `ports` were taken and the system chose a free port because `ports` contained a `0`.
+    EphemeralFallback,
+}
+
+static ACTIVE_SERVERS: AtomicUsize = AtomicUsize::new(0);
+// The unit tests run in parallel and would all share the cap.
+static MAX_SERVERS: AtomicUsize = AtomicUsize::new(if cfg!(test) { usize::MAX } else { 16 });
+
+/// Sets how many servers can be reserved or running at the same time. Starting more fails with an error.
+/// This is a safety rail against app code that starts servers in a loop without ever stopping them.
+///
+/// Default: `16`.
+pub fn set_max_servers(max: usize) {
+    MAX_SERVERS.store(max, Ordering::SeqCst);
+}
+
+/// The error wrapped in the `std::io::Error` returned if too many servers are running, see [`set_max_servers`].
+///
+/// Use `err.get_ref().and_then(|err| err.downcast_ref::<TooManyServers>())` to tell it apart from other errors.
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct TooManyServers {
+    /// The maximum at the time the server was started.
+    pub max: usize,
+}
+
+impl std::fmt::Display for TooManyServers {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Too many servers: {} servers are already running",
+            self.max
+        )
+    }
+}
+
+impl std::error::Error for TooManyServers {}
+
+/// Counts towards [`set_max_servers`] until dropped together with the server.
+struct ServerSlot;
+
+impl ServerSlot {
+    fn acquire() -> Result<Self, std::io::Error> {
+        let max = MAX_SERVERS.load(Ordering::SeqCst);
+        ACTIVE_SERVERS
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |active| {
+                (active < max).then(|| active + 1)
+            })
+            .map(|_| ServerSlot)
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, TooManyServers { max }))
+    }
+}
+
+impl Drop for ServerSlot {
+    fn drop(&mut self) {
+        ACTIVE_SERVERS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(feature = "open")]
+fn validate_open_url(open_url: &str) -> Result<(), std::io::Error> {
+    let parsed = url::Url::parse(open_url).map_err(|err| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("Invalid `on_success_open_url`: {}", err),
+        )
+    })?;
+    if matches!(parsed.scheme(), "file" | "javascript" | "data") {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!(
+                "Invalid `on_success_open_url`: the `{}` scheme is not allowed",
+                parsed.scheme()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// A bound but not yet accepting server, see [`reserve`].
+pub struct Reserved {
+    listener: TcpListener,
+    config: OauthConfig,
+    report: BindReport,
+    slot: ServerSlot,
+}
+
+impl Reserved {
+    /// The port the server is bound to.
+    #[must_use]
+    pub fn port(&self) -> u16 {
+        self.report.selected_port
+    }
+
+    /// The loopback address the server is bound to.
+    #[must_use]
+    pub fn local_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.report.family.loopback(), self.report.selected_port)
+    }
+
+    /// Describes how the port was chosen, for logging and diagnostics.
+    #[must_use]
+    pub fn bind_report(&self) -> &BindReport {
+        &self.report
+    }
+
+    /// Starts accepting connections. Returns the port its listening on.
+    ///
+    /// Because of the unprotected localhost port, you _must_ verify the URL in the handler function.
+    ///
+    /// # Arguments
+    ///
+    /// * `handler` - Closure which will be executed on a successful connection. It receives the full URL as a String.
+    ///
+    /// # Errors
+    ///
+    /// - Returns `std::io::Error` if the server thread couldn't be spawned.
+    ///
+    /// # Panics
+    ///
+    /// The seperate server thread can panic if its unable to send the html response to the client. This may change after more real world testing.
+    pub fn accept<F: FnMut(String) + Send + 'static>(
+        self,
+        mut handler: F,
+    ) -> Result<u16, std::io::Error> {
+        let Reserved {
+            listener,
+            config,
+            report,
+            slot,
+        } = self;
+        let port = report.selected_port;
+
+        let thread_name = match &config.name {
+            Some(name) => format!("oauth-{}-{}", name, port),
+            None => format!("oauth-{}", port),
+        };
+
+        thread::Builder::new().name(thread_name).spawn(move || {
+            let _slot = slot;
+
+            #[cfg(feature = "test-redirect")]
+            if let Ok(url) = std::env::var(TEST_REDIRECT_ENV) {
+                match validate_issuer(&config, &url) {
+                    Ok(()) => {
+                        log::warn!(
+                            "{} is set. Skipping the real redirect and shutting down...",
+                            TEST_REDIRECT_ENV
+                        );
+                        run_handler(&config, &mut handler, url);
+                        return;
                     }
+                    Err(reason) => log::error!(
+                        "Ignoring {}: {} Waiting for a real redirect...",
+                        TEST_REDIRECT_ENV,
+                        reason
+                    ),
                 }
-                Err(err) => {
-                    log::error!("Error reading incoming connection: {}", err.to_string());
+            }
+
+            for conn in listener.incoming() {
+                match conn {
+                    Ok(conn) => {
+                        configure_connection(&conn, &config);
+                        if let Some(url) = handle_connection(conn, &config, port) {
+                            // Using an empty string to communicate that a shutdown was requested.
+                            if !url.is_empty() {
+                                run_handler(&config, &mut handler, url);
+                            }
+                            // TODO: Check if exiting here is always okay.
+                            break;
+                        }
+                    }
+                    Err(err) => match accept_error_action(err.kind()) {
+                        AcceptAction::Retry => {
+                            log::debug!("Error reading incoming connection, retrying: {}", err);
+                        }
+                        AcceptAction::Backoff => {
+                            log::error!("Error reading incoming connection: {}", err);
+                            thread::sleep(config.accept_backoff.unwrap_or(DEFAULT_ACCEPT_BACKOFF));
+                        }
+                        AcceptAction::Stop => {
+                            log::error!(
+                                "Error accepting connections, stopping the server: {}",
+                                err
+                            );
+                            break;
+                        }
+                    },
                 }
             }
+
+            drain_pending_connections(&listener, &config);
+        })?;
+
+        Ok(port)
+    }
+}
+
+/// What the accept loop does after accepting a connection failed.
+#[derive(Debug, PartialEq, Eq)]
+enum AcceptAction {
+    /// Accept the next connection right away.
+    Retry,
+    /// Wait for `accept_backoff` before accepting the next connection.
+    Backoff,
+    /// Stop the server.
+    Stop,
+}
+
+fn accept_error_action(kind: std::io::ErrorKind) -> AcceptAction {
+    match kind {
+        // The client reset the connection before it was accepted, or a signal interrupted the call.
+        // Only that one attempt failed.
+        std::io::ErrorKind::ConnectionAborted
+        | std::io::ErrorKind::ConnectionReset
+        | std::io::ErrorKind::Interrupted => AcceptAction::Retry,
+        // EINVAL means the socket isn't listening anymore, retrying won't help.
+        std::io::ErrorKind::InvalidInput => AcceptAction::Stop,
+        // Errors like running out of file descriptors usually persist for a while,
+        // so don't busy-loop on them.
+        _ => AcceptAction::Backoff,
+    }
+}
+
+/// Starts the localhost (using `127.0.0.1` or `[::1]`, see [`OauthConfig::addr_family`]) server.
+/// Returns the port its listening on and a future
+/// which resolves with the first captured URL.
+///
+/// This is meant for async apps that don't want to wire up their own channel between the handler and their code.
+/// The future resolves exactly once. Like with [`start_with_config`], the server stops after the first redirect,
+/// so you don't have to stop it yourself afterwards.
+///
+/// Because of the unprotected localhost port, you _must_ verify the URL the future resolves with.
+///
+/// # Arguments
+///
+/// * `config` - Configuration the server should use, see [`OauthConfig`]
+///
+/// # Errors
+///
+/// - Returns `std::io::Error` if the server creation fails.
+/// - The future resolves with a `std::io::Error` of kind `Interrupted` if the server was stopped via [`cancel`] before a URL was captured.
+pub fn start_as_future(
+    config: OauthConfig,
+) -> Result<(u16, impl Future<Output = Result<String, std::io::Error>>), std::io::Error> {
+    let shared = Arc::new(Mutex::new(Oneshot::default()));
+    let sender = OneshotSender(shared.clone());
+
+    let port = start_with_config(config, move |url| sender.send(Ok(url)))?;
+
+    Ok((port, OneshotReceiver(shared)))
+}
+
+#[derive(Default)]
+struct Oneshot {
+    sent: bool,
+    value: Option<Result<String, std::io::Error>>,
+    waker: Option<Waker>,
+}
+
+struct OneshotSender(Arc<Mutex<Oneshot>>);
+
+impl OneshotSender {
+    fn send(&self, value: Result<String, std::io::Error>) {
+        let mut oneshot = self.0.lock().unwrap();
+        if oneshot.sent {
+            return;
         }
-    });
+        oneshot.sent = true;
+        oneshot.value = Some(value);
+        if let Some(waker) = oneshot.waker.take() {
+            waker.wake();
+        }
+    }
+}
 
-    Ok(port)
+impl Drop for OneshotSender {
+    // The handler (and with it the sender) is dropped once the server thread exits.
+    fn drop(&mut self) {
+        self.send(Err(std::io::Error::new(
+            std::io::ErrorKind::Interrupted,
+            "The server was stopped before a URL was captured",
+        )));
+    }
 }
 
-fn handle_connection(mut conn: TcpStream, response: Option<&str>, port: u16) -> Option<String> {
-    let mut buffer = [0; 4048];
-    if let Err(io_err) = conn.read(&mut buffer) {
-        log::error!("Error reading incoming connection: {}", io_err.to_string());
-    };
-    if buffer[..4] == EXIT {
-        return Some(String::new());
+struct OneshotReceiver(Arc<Mutex<Oneshot>>);
+
+impl Future for OneshotReceiver {
+    type Output = Result<String, std::io::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut oneshot = self.0.lock().unwrap();
+        match oneshot.value.take() {
+            Some(value) => Poll::Ready(value),
+            None => {
+                oneshot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Applies the socket options of the config to an accepted connection.
+fn configure_connection(conn: &TcpStream, config: &OauthConfig) {
+    if let Some(linger) = config.linger {
+        if let Err(err) = socket2::SockRef::from(conn).set_linger(Some(linger)) {
+            log::error!("Error setting SO_LINGER on incoming connection: {}", err);
+        }
+    }
+    if config.nodelay.unwrap_or(true) {
+        if let Err(io_err) = conn.set_nodelay(true) {
+            log::error!(
+                "Error setting TCP_NODELAY on incoming connection: {}",
+                io_err
+            );
+        }
+    }
+}
+
+fn handle_connection(mut conn: TcpStream, config: &OauthConfig, port: u16) -> Option<String> {
+    let mut buffer = Vec::with_capacity(4048);
+    let mut chunk = [0; 4048];
+    loop {
+        let read = match conn.read(&mut chunk) {
+            Ok(read) => read,
+            Err(io_err) => {
+                log::error!("Error reading incoming connection: {}", io_err);
+                return None;
+            }
+        };
+        buffer.extend_from_slice(&chunk[..read]);
+        if buffer.starts_with(&EXIT) {
+            return Some(String::new());
+        }
+
+        let mut headers = [httparse::EMPTY_HEADER; 16];
+        match httparse::Request::new(&mut headers).parse(&buffer) {
+            Ok(httparse::Status::Complete(_)) => break,
+            // Browsers open speculative connections that are closed without sending anything, that's not an error.
+            Ok(httparse::Status::Partial) if read == 0 && buffer.is_empty() => {
+                log::debug!("Client closed the connection without sending a request.");
+                return None;
+            }
+            Ok(httparse::Status::Partial) if read == 0 => {
+                log::error!("Client closed the connection before sending a complete request.");
+                return None;
+            }
+            Ok(httparse::Status::Partial) if buffer.len() >= MAX_REQUEST_SIZE => {
+                let reason = format!("Request is larger than {} bytes.", MAX_REQUEST_SIZE);
+                log::error!("Rejecting incoming request: {}", reason);
+                write_error_response(
+                    &mut conn,
+                    config,
+                    "431 Request Header Fields Too Large",
+                    &[],
+                    &reason,
+                );
+                return None;
+            }
+            Ok(httparse::Status::Partial) => {}
+            Err(httparse::Error::TooManyHeaders) => {
+                let reason = "Request contains too many headers.";
+                log::error!("Rejecting incoming request: {}", reason);
+                write_error_response(
+                    &mut conn,
+                    config,
+                    "431 Request Header Fields Too Large",
+                    &[],
+                    reason,
+                );
+                return None;
+            }
+            Err(err) => {
+                let reason = format!("Malformed request: {}.", err);
+                log::error!("Rejecting incoming request: {}", reason);
+                write_error_response(&mut conn, config, "400 Bad Request", &[], &reason);
+                return None;
+            }
+        }
     }
 
     let mut headers = [httparse::EMPTY_HEADER; 16];
     let mut request = httparse::Request::new(&mut headers);
     request.parse(&buffer).ok()?;
 
-    let path = request.path.unwrap_or_default();
+    let method = request.method.unwrap_or_default();
+    let allowed_methods = match &config.allowed_methods {
+        Some(methods) => methods.iter().map(String::as_str).collect(),
+        None => DEFAULT_ALLOWED_METHODS.to_vec(),
+    };
+    if !allowed_methods.contains(&method) {
+        let reason = format!("Method `{}` is not allowed.", method);
+        log::warn!("Rejecting incoming request: {}", reason);
+        write_error_response(
+            &mut conn,
+            config,
+            "405 Method Not Allowed",
+            &[("Allow", &allowed_methods.join(", "))],
+            &reason,
+        );
+        return None;
+    }
+    if method == "OPTIONS" {
+        let allow = allowed_methods.join(", ");
+        let mut headers = vec![("Allow", allow.as_str())];
+        let res = match &config.options_response {
+            Some(over) => {
+                over.extend_headers(&mut headers);
+                let status = over.status_or("204 No Content");
+                if status_allows_body(status) {
+                    let body = over.body.as_deref().unwrap_or_default();
+                    write_response(&mut conn, status, &headers, body)
+                } else {
+                    write_bodyless_response(&mut conn, status, &headers, None)
+                }
+            }
+            None => write_bodyless_response(&mut conn, "204 No Content", &headers, None),
+        };
+        if let Err(io_err) = res {
+            log::error!("Error sending OPTIONS response: {}", io_err);
+        }
+        return None;
+    }
+
+    let path = route_path(request.path.unwrap_or_default());
 
     if path == "/exit" {
         return Some(String::new());
     };
 
+    // With `trust_forwarded_headers` the proxy is intentional, no need to warn about it.
+    if !config.trust_forwarded_headers {
+        if let Some(header) = headers.iter().find(|header| {
+            PROXY_HEADERS
+                .iter()
+                .any(|h| header.name.eq_ignore_ascii_case(h))
+        }) {
+            log::warn!(
+                "Incoming request contains a `{}` header, it probably went through a proxy. If the redirect doesn't work, make sure your system proxy excludes localhost and 127.0.0.1.",
+                header.name
+            );
+        }
+    }
+
     let mut is_localhost = false;
+    let mut forwarded_proto = None;
+    let mut forwarded_host = None;
 
     for header in &headers {
         if header.name == "Full-Url" {
-            return Some(String::from_utf8_lossy(header.value).to_string());
+            let url = String::from_utf8_lossy(header.value).to_string();
+            if let Err(reason) = validate_issuer(config, &url) {
+                log::error!("Rejecting incoming request: {}", reason);
+                write_error_response(&mut conn, config, "400 Bad Request", &[], &reason);
+                return None;
+            }
+            return Some(url);
         } else if header.name == "Host" {
             is_localhost = String::from_utf8_lossy(header.value).starts_with("localhost");
+        } else if header.name.eq_ignore_ascii_case("X-Forwarded-Proto") {
+            forwarded_proto = std::str::from_utf8(header.value).ok();
+        } else if header.name.eq_ignore_ascii_case("X-Forwarded-Host") {
+            forwarded_host = std::str::from_utf8(header.value).ok();
         }
     }
     if path == "/cb" {
@@ -136,99 +989,503 @@ fn handle_connection(mut conn: TcpStream, response: Option<&str>, port: u16) ->
         );
     }
 
+    let origin = match (forwarded_proto, forwarded_host) {
+        // The values end up in the script, so only accept plain schemes and hosts.
+        (Some(proto), Some(host))
+            if config.trust_forwarded_headers
+                && matches!(proto, "http" | "https")
+                && !host.is_empty()
+                && host.chars().all(|c| {
+                    c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | ':' | '[' | ']')
+                }) =>
+        {
+            format!("{}://{}", proto, host)
+        }
+        _ => format!(
+            "http://{}:{}",
+            if is_localhost {
+                "localhost"
+            } else {
+                config.addr_family.unwrap_or(AddrFamily::V4).loopback_host()
+            },
+            port
+        ),
+    };
     let script = format!(
-        r#"<script>fetch("http://{}:{}/cb",{{headers:{{"Full-Url":window.location.href}}}})</script>"#,
-        if is_localhost {
-            "localhost"
-        } else {
-            "127.0.0.1"
-        },
-        port
+        r#"<script>fetch("{}/cb",{{headers:{{"Full-Url":window.location.href}}}})</script>"#,
+        origin
     );
-    let response = match response {
-        Some(s) if s.contains("<head>") => s.replace("<head>", &format!("<head>{}", script)),
-        Some(s) if s.contains("<body>") => {
-            s.replace("<body>", &format!("<head>{}</head><body>", script))
-        }
-        Some(s) => {
+    let response = match effective_response(config, None) {
+        s if s.contains("<head>") => s.replace("<head>", &format!("<head>{}", script)),
+        s if s.contains("<body>") => s.replace("<body>", &format!("<head>{}</head><body>", script)),
+        s => {
             log::warn!(
                 "`response` does not contain a body or head element. Prepending a head element..."
             );
             format!("<head>{}</head>{}", script, s)
         }
-        None => format!(
-            "<html><head>{}</head><body>Please return to the app.</body></html>",
-            script
-        ),
     };
 
     // TODO: Test if unwrapping here is safe (enough).
-    conn.write_all(
-        format!(
-            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}",
-            response.len(),
-            response
-        )
-        .as_bytes(),
-    )
-    .unwrap();
-    conn.flush().unwrap();
+    if method == "HEAD" {
+        let mut headers = config.html_headers();
+        let status = match &config.head_response {
+            Some(over) => {
+                over.extend_headers(&mut headers);
+                over.status_or("200 OK")
+            }
+            None => "200 OK",
+        };
+        let content_length = status_allows_body(status).then_some(response.len());
+        write_bodyless_response(&mut conn, status, &headers, content_length).unwrap();
+    } else {
+        write_response(&mut conn, "200 OK", &config.html_headers(), &response).unwrap();
+    }
 
     None
 }
 
-/// Stops the currently running server behind the provided port without executing the handler.
-/// Alternatively you can send a request to http://127.0.0.1:port/exit
-///
-/// # Errors
+/// Returns the part of the request target that is compared against the special routes (`/exit` and `/cb`).
 ///
-/// - Returns `std::io::Error` if the server couldn't be reached.
-pub fn cancel(port: u16) -> Result<(), std::io::Error> {
-    // Using tcp instead of something global-ish like an AtomicBool,
-    // so we don't have to dive into the set_nonblocking madness.
-    let mut stream = TcpStream::connect(SocketAddr::from(([127, 0, 0, 1], port)))?;
-    stream.write_all(&EXIT)?;
-    stream.flush()?;
-
-    Ok(())
+/// Only the path without the query is compared. It is not percent-decoded, so an encoded slash (`%2F`)
+/// is a literal character and not a separator, e.g. `/cb%2Fx` never matches `/cb`.
+/// The captured URL itself is passed on untouched.
+fn route_path(target: &str) -> &str {
+    target.split('?').next().unwrap_or_default()
 }
 
-mod plugin_impl {
-    use tauri::{Manager, Runtime, Window};
+fn run_handler<F: FnMut(String)>(config: &OauthConfig, handler: &mut F, url: String) {
+    match &config.transform_url {
+        Some(transform) => handler(transform(url)),
+        None => handler(url),
+    }
+    #[cfg(feature = "open")]
+    if let Some(open_url) = &config.on_success_open_url {
+        if let Err(err) = open_browser(open_url) {
+            log::error!("Error opening `on_success_open_url`: {}", err);
+        }
+    }
+}
+
+/// Answers the connections that are already queued when the server shuts down,
+/// so the clients get a message instead of a connection reset.
+fn drain_pending_connections(listener: &TcpListener, config: &OauthConfig) {
+    if let Err(io_err) = listener.set_nonblocking(true) {
+        log::error!("Error draining pending connections: {}", io_err);
+        return;
+    }
+
+    let response = config
+        .shutdown_response
+        .as_deref()
+        .unwrap_or("<html><body>This login session has ended, please retry.</body></html>");
+
+    for conn in listener.incoming() {
+        match conn {
+            Ok(mut conn) => {
+                configure_connection(&conn, config);
+                // Some platforms let accepted connections inherit the listener's non-blocking mode.
+                // Read (and discard) the request first, otherwise closing the connection may reset it before the response arrives.
+                let mut buffer = [0; 4048];
+                if let Err(io_err) = conn
+                    .set_nonblocking(false)
+                    .and_then(|_| conn.set_read_timeout(Some(Duration::from_secs(1))))
+                    .and_then(|_| conn.read(&mut buffer))
+                {
+                    log::error!("Error reading pending connection: {}", io_err);
+                }
+                if let Err(io_err) = write_response(
+                    &mut conn,
+                    "503 Service Unavailable",
+                    &config.html_headers(),
+                    response,
+                ) {
+                    log::error!("Error sending shutdown response: {}", io_err);
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+            Err(err) => {
+                log::error!("Error draining pending connections: {}", err);
+                break;
+            }
+        }
+    }
+}
+
+/// Checks the `iss` parameter of the captured URL against [`OauthConfig::expected_issuer`], if set.
+/// On a mismatch `on_invalid_issuer` is executed and the reason is returned.
+fn validate_issuer(config: &OauthConfig, url: &str) -> Result<(), String> {
+    let expected_issuer = match &config.expected_issuer {
+        Some(expected_issuer) => expected_issuer,
+        None => return Ok(()),
+    };
+    let issuer = issuer_param(url);
+    if issuer.as_deref() == Some(expected_issuer.as_str()) {
+        return Ok(());
+    }
+    let reason = match &issuer {
+        Some(issuer) => format!("Issuer `{}` doesn't match the expected issuer.", issuer),
+        None => "Missing `iss` parameter.".to_string(),
+    };
+    if let Some(on_invalid_issuer) = &config.on_invalid_issuer {
+        on_invalid_issuer(issuer);
+    }
+    Err(reason)
+}
+
+/// Returns the `iss` parameter of the authorization response, looking at the query first and the fragment second.
+fn issuer_param(url: &str) -> Option<String> {
+    let url = url::Url::parse(url).ok()?;
+    let find_iss = |pairs: url::form_urlencoded::Parse<'_>| {
+        pairs
+            .into_iter()
+            .find(|(key, _)| key == "iss")
+            .map(|(_, value)| value.into_owned())
+    };
+    find_iss(url.query_pairs())
+        .or_else(|| find_iss(url::form_urlencoded::parse(url.fragment()?.as_bytes())))
+}
+
+fn write_response(
+    conn: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, &str)],
+    body: &str,
+) -> Result<(), std::io::Error> {
+    let mut response = response_head(status, headers, Some(body.len()));
+    response.push_str(body);
+    conn.write_all(response.as_bytes())?;
+    conn.flush()
+}
+
+/// Like [`write_response`] but without a body, for `HEAD` requests and `204 No Content` responses.
+/// `content_length` is the length of the body a `GET` request would get,
+/// and must be `None` if the status doesn't allow a body, see [`status_allows_body`].
+fn write_bodyless_response(
+    conn: &mut TcpStream,
+    status: &str,
+    headers: &[(&str, &str)],
+    content_length: Option<usize>,
+) -> Result<(), std::io::Error> {
+    conn.write_all(response_head(status, headers, content_length).as_bytes())?;
+    conn.flush()
+}
+
+/// Whether a response with this status may have a body and a `Content-Length`. `1xx`, `204` and `304` responses can't.
+fn status_allows_body(status: &str) -> bool {
+    !(status.starts_with('1') || status.starts_with("204") || status.starts_with("304"))
+}
+
+fn response_head(status: &str, headers: &[(&str, &str)], content_length: Option<usize>) -> String {
+    let mut head = format!("HTTP/1.1 {}\r\n", status);
+    for (name, value) in headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    if let Some(content_length) = content_length {
+        head.push_str(&format!("Content-Length: {}\r\n", content_length));
+    }
+    head.push_str("\r\n");
+    head
+}
+
+fn write_error_response(
+    conn: &mut TcpStream,
+    config: &OauthConfig,
+    status: &str,
+    headers: &[(&str, &str)],
+    reason: &str,
+) {
+    let body = if config.verbose_rejections {
+        reason
+    } else {
+        ""
+    };
+    if let Err(io_err) = write_response(conn, status, headers, body) {
+        log::error!("Error sending {} response: {}", status, io_err);
+    }
+}
+
+/// Opens the URL in the system's default browser, for example the authorization URL of your oauth provider.
+///
+/// Requires the `open` feature.
+///
+/// In headless environments (e.g. CI, SSH sessions or containers) there may be no browser to launch.
+/// Consider falling back to showing the URL to the user so they can open it manually if this returns an error.
+///
+/// # Errors
+///
+/// - Returns `std::io::Error` if no browser could be launched.
+#[cfg(feature = "open")]
+pub fn open_browser(url: &str) -> Result<(), std::io::Error> {
+    open::that(url)
+}
+
+/// Stops the currently running server behind the provided port without executing the handler.
+/// Alternatively you can send a request to http://127.0.0.1:port/exit
+///
+/// Tries `127.0.0.1` first and `[::1]` second, so it works regardless of [`OauthConfig::addr_family`].
+/// If servers of both families run on the same port, this stops the `127.0.0.1` one, use [`cancel_addr`] instead.
+///
+/// # Errors
+///
+/// - Returns `std::io::Error` if the server couldn't be reached.
+pub fn cancel(port: u16) -> Result<(), std::io::Error> {
+    send_exit(
+        [AddrFamily::V4, AddrFamily::V6]
+            .map(|family| SocketAddr::new(family.loopback(), port))
+            .as_slice(),
+    )
+}
+
+/// Stops the currently running server behind the provided address without executing the handler,
+/// see [`Reserved::local_addr`].
+///
+/// # Errors
+///
+/// - Returns `std::io::Error` if the server couldn't be reached.
+pub fn cancel_addr(addr: SocketAddr) -> Result<(), std::io::Error> {
+    send_exit(&[addr])
+}
+
+fn send_exit(addrs: &[SocketAddr]) -> Result<(), std::io::Error> {
+    // Using tcp instead of something global-ish like an AtomicBool,
+    // so we don't have to dive into the set_nonblocking madness.
+    let mut stream = TcpStream::connect(addrs)?;
+    stream.write_all(&EXIT)?;
+    stream.flush()?;
+
+    Ok(())
+}
+
+mod plugin_impl {
+    use std::{
+        collections::HashMap,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Mutex,
+        },
+        time::Instant,
+    };
+
+    use serde::Serialize;
+    use tauri::{AppHandle, Manager, Runtime, Window};
+
+    use crate::AddrFamily;
+
+    /// The servers started via the `start` command, keyed by their address.
+    #[derive(Default)]
+    pub(crate) struct Servers(Mutex<HashMap<SocketAddr, ServerEntry>>);
+
+    struct ServerEntry {
+        // Distinguishes a server from a later one that got the same address.
+        id: u64,
+        name: Option<String>,
+        target_window: Option<String>,
+        started_at: Instant,
+    }
+
+    /// Describes a running server, returned by the `list_servers` command.
+    #[derive(Clone, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    pub(crate) struct ServerInfo {
+        port: u16,
+        addr_family: AddrFamily,
+        name: Option<String>,
+        target_window: Option<String>,
+        uptime_secs: u64,
+    }
+
+    /// The payload of the `oauth://started` event.
+    #[derive(Clone, Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct Started {
+        port: u16,
+        attempted_ports: Vec<u16>,
+    }
+
+    static NEXT_SERVER_ID: AtomicU64 = AtomicU64::new(0);
+
+    /// Removes the server from [`Servers`] once the server thread exits and drops the handler.
+    struct ServerGuard<R: Runtime> {
+        app: AppHandle<R>,
+        id: u64,
+        addr: SocketAddr,
+    }
+
+    impl<R: Runtime> ServerGuard<R> {
+        fn new(
+            app: AppHandle<R>,
+            addr: SocketAddr,
+            name: Option<String>,
+            target_window: Option<String>,
+        ) -> Self {
+            let id = NEXT_SERVER_ID.fetch_add(1, Ordering::Relaxed);
+            app.state::<Servers>().0.lock().unwrap().insert(
+                addr,
+                ServerEntry {
+                    id,
+                    name,
+                    target_window,
+                    started_at: Instant::now(),
+                },
+            );
+            Self { app, id, addr }
+        }
+    }
+
+    impl<R: Runtime> Drop for ServerGuard<R> {
+        fn drop(&mut self) {
+            let state = self.app.state::<Servers>();
+            let mut servers = state.0.lock().unwrap();
+            if servers.get(&self.addr).map(|entry| entry.id) == Some(self.id) {
+                servers.remove(&self.addr);
+            }
+        }
+    }
+
+    /// Cancels all servers whose `targetWindow` was the destroyed window.
+    pub(crate) fn cancel_window_servers<R: Runtime>(app: &AppHandle<R>, label: &str) {
+        let addrs: Vec<SocketAddr> = app
+            .state::<Servers>()
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, entry)| entry.target_window.as_deref() == Some(label))
+            .map(|(addr, _)| *addr)
+            .collect();
+        for addr in addrs {
+            if let Err(err) = crate::cancel_addr(addr) {
+                log::error!(
+                    "Error cancelling server on {} of closed window `{}`: {}",
+                    addr,
+                    label,
+                    err
+                );
+            }
+        }
+    }
+
+    fn emit<R: Runtime, S: Serialize + Clone>(
+        window: &Window<R>,
+        global: bool,
+        event: &str,
+        payload: S,
+    ) {
+        let res = if global {
+            window.app_handle().emit_all(event, payload)
+        } else {
+            window.emit(event, payload)
+        };
+        if let Err(emit_err) = res {
+            log::error!("Error emitting {} event: {}", event, emit_err)
+        };
+    }
 
     #[tauri::command]
     pub(crate) fn start<R: Runtime>(
         window: Window<R>,
         config: Option<super::OauthConfig>,
+        target_window: Option<String>,
+        emit_global: Option<bool>,
     ) -> Result<u16, String> {
+        let emit_global = emit_global.unwrap_or_default();
+        let window = match &target_window {
+            Some(label) => window
+                .get_window(label)
+                .ok_or_else(|| format!("Window with label `{}` not found", label))?,
+            None => window,
+        };
+
         let mut config = config.unwrap_or_default();
-        if config.response.is_none() {
-            config.response = window
-                .config()
-                .plugins
-                .0
-                .get("oauth")
-                .map(|v| v.as_str().unwrap().to_string().into());
-        }
+        let conf_json_fallback = window
+            .config()
+            .plugins
+            .0
+            .get("oauth")
+            .and_then(|v| v.as_str())
+            .map(ToString::to_string);
+        config.response = Some(
+            crate::effective_response(&config, conf_json_fallback.as_deref())
+                .into_owned()
+                .into(),
+        );
+        let invalid_issuer_window = window.clone();
+        config.on_invalid_issuer = Some(Box::new(move |issuer| {
+            emit(
+                &invalid_issuer_window,
+                emit_global,
+                "oauth://invalid_issuer",
+                issuer,
+            )
+        }));
 
-        crate::start_with_config(config, move |url| match url::Url::parse(&url) {
-            Ok(_) => {
-                if let Err(emit_err) = window.emit("oauth://url", url) {
-                    log::error!("Error emitting oauth://url event: {}", emit_err)
-                };
-            }
-            Err(err) => {
-                if let Err(emit_err) = window.emit("oauth://invalid-url", err.to_string()) {
-                    log::error!("Error emitting oauth://invalid-url event: {}", emit_err)
-                };
-            }
-        })
-        .map_err(|err| err.to_string())
+        let name = config.name.clone();
+        let reserved = crate::reserve(config).map_err(|err| err.to_string())?;
+
+        // Emitted before the server accepts connections and before the command resolves,
+        // so listeners registered before `invoke` always see it before any `oauth://url` event.
+        let report = reserved.bind_report();
+        emit(
+            &window,
+            emit_global,
+            "oauth://started",
+            Started {
+                port: report.selected_port,
+                attempted_ports: report.attempted.clone(),
+            },
+        );
+
+        let guard = ServerGuard::new(
+            window.app_handle(),
+            reserved.local_addr(),
+            name,
+            target_window,
+        );
+
+        reserved
+            .accept(move |url| {
+                let _guard = &guard;
+                match url::Url::parse(&url) {
+                    Ok(_) => emit(&window, emit_global, "oauth://url", url),
+                    Err(err) => emit(&window, emit_global, "oauth://invalid-url", err.to_string()),
+                }
+            })
+            .map_err(|err| err.to_string())
     }
 
     #[tauri::command]
-    pub(crate) fn cancel(port: u16) -> Result<(), String> {
-        crate::cancel(port).map_err(|err| err.to_string())
+    pub(crate) fn list_servers<R: Runtime>(app: AppHandle<R>) -> Vec<ServerInfo> {
+        let mut servers: Vec<ServerInfo> = app
+            .state::<Servers>()
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(addr, entry)| ServerInfo {
+                port: addr.port(),
+                addr_family: if addr.is_ipv6() {
+                    AddrFamily::V6
+                } else {
+                    AddrFamily::V4
+                },
+                name: entry.name.clone(),
+                target_window: entry.target_window.clone(),
+                uptime_secs: entry.started_at.elapsed().as_secs(),
+            })
+            .collect();
+        servers.sort_by_key(|server| (server.port, server.addr_family == AddrFamily::V6));
+        servers
+    }
+
+    #[tauri::command]
+    pub(crate) fn cancel(port: u16, addr_family: Option<AddrFamily>) -> Result<(), String> {
+        match addr_family {
+            Some(family) => crate::cancel_addr(SocketAddr::new(family.loopback(), port)),
+            None => crate::cancel(port),
+        }
+        .map_err(|err| err.to_string())
     }
 }
 
@@ -237,12 +1494,682 @@ mod plugin_impl {
 ///
 /// Note for the `start()` command: If `response` is not provided it will fall back to the config
 /// in tauri.conf.json if set and will fall back to the library's default, see [`OauthConfig`].
+/// The events are emitted to the window that invoked the command, unless `targetWindow` is set to the label
+/// of another window. Use this if the flow is started from a popup that may be closed before the redirect arrives.
+/// Set `emitGlobal` to `true` to emit the events to all windows instead, which is the more robust choice for most apps.
+///
+/// The `oauth://started` event carries `{ port, attemptedPorts }` and is emitted before the command resolves and before
+/// the server accepts connections. Listening for it before calling `invoke` is the race-free way to get the port.
+/// `attemptedPorts` lists the ports that were tried in order, see [`BindReport::attempted`].
+///
+/// If `expected_issuer` is set and a redirect's `iss` parameter doesn't match it, `oauth://invalid_issuer` is emitted
+/// with the received `iss` (or `null`) instead of `oauth://url`, and the server keeps waiting.
+///
+/// Servers started with `targetWindow` are tied to that window and get cancelled when it is destroyed.
+///
+/// The `list_servers()` command returns the port, `addrFamily`, `name`, `targetWindow` and uptime in seconds of every server
+/// started via `start()` that is still running.
+///
+/// The `cancel()` command takes the `port` and an optional `addrFamily` (`"V4"` or `"V6"`). Pass the family if
+/// servers of both families may run on the same port, see [`cancel`] and [`cancel_addr`].
 #[must_use]
 pub fn init<R: Runtime>() -> TauriPlugin<R> {
     Builder::new("oauth")
         .invoke_handler(tauri::generate_handler![
             plugin_impl::start,
-            plugin_impl::cancel
+            plugin_impl::cancel,
+            plugin_impl::list_servers
         ])
+        .setup(|app| {
+            app.manage(plugin_impl::Servers::default());
+            Ok(())
+        })
+        .on_event(|app, event| {
+            if let RunEvent::WindowEvent {
+                label,
+                event: WindowEvent::Destroyed,
+                ..
+            } = event
+            {
+                plugin_impl::cancel_window_servers(app, label);
+            }
+        })
         .build()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{net::Shutdown, sync::mpsc};
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// Starts a server that forwards the captured URLs to the returned receiver.
+    /// The receiver disconnects once the server stopped and dropped the handler.
+    fn serve(config: OauthConfig) -> (u16, mpsc::Receiver<String>) {
+        let (tx, rx) = mpsc::channel();
+        let port = start_with_config(config, move |url| {
+            let _ = tx.send(url);
+        })
+        .unwrap();
+        (port, rx)
+    }
+
+    /// Sends the raw request and returns everything the server sent back before closing the connection.
+    fn send(port: u16, request: &[u8]) -> String {
+        let mut stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        stream.set_read_timeout(Some(TIMEOUT)).unwrap();
+        stream.write_all(request).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+
+    struct ThreadWaker(thread::Thread);
+
+    impl std::task::Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Polls the future on the current thread until it resolves or `TIMEOUT` elapsed.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        let mut future = Box::pin(future);
+        let waker = Waker::from(Arc::new(ThreadWaker(thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        let deadline = std::time::Instant::now() + TIMEOUT;
+        loop {
+            if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+                return output;
+            }
+            assert!(
+                std::time::Instant::now() < deadline,
+                "the future didn't resolve"
+            );
+            thread::park_timeout(TIMEOUT);
+        }
+    }
+
+    fn assert_stopped(rx: &mpsc::Receiver<String>) {
+        assert_eq!(
+            rx.recv_timeout(TIMEOUT),
+            Err(mpsc::RecvTimeoutError::Disconnected)
+        );
+    }
+
+    #[test]
+    fn effective_response_prefers_config() {
+        let mut config = OauthConfig::new();
+        config.response = Some("<body>config</body>".into());
+        assert_eq!(
+            effective_response(&config, Some("<body>conf json</body>")),
+            "<body>config</body>"
+        );
+    }
+
+    #[test]
+    fn effective_response_falls_back_to_conf_json() {
+        assert_eq!(
+            effective_response(&OauthConfig::new(), Some("<body>conf json</body>")),
+            "<body>conf json</body>"
+        );
+    }
+
+    #[test]
+    fn effective_response_falls_back_to_default() {
+        assert_eq!(
+            effective_response(&OauthConfig::new(), None),
+            DEFAULT_RESPONSE
+        );
+    }
+
+    #[test]
+    fn disallowed_method_is_rejected() {
+        let (port, rx) = serve(OauthConfig::new());
+        assert_eq!(
+            send(port, b"DELETE / HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, POST, OPTIONS\r\nContent-Length: 0\r\n\r\n"
+        );
+        cancel(port).unwrap();
+        assert_stopped(&rx);
+    }
+
+    #[test]
+    fn head_is_rejected_by_default() {
+        let (port, rx) = serve(OauthConfig::new());
+        assert_eq!(
+            send(port, b"HEAD / HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 405 Method Not Allowed\r\nAllow: GET, POST, OPTIONS\r\nContent-Length: 0\r\n\r\n"
+        );
+        cancel(port).unwrap();
+        assert_stopped(&rx);
+    }
+
+    #[test]
+    fn route_path_strips_only_the_query() {
+        assert_eq!(route_path("/exit?x"), "/exit");
+        assert_eq!(route_path("/cb%2Fx"), "/cb%2Fx");
+        assert_eq!(route_path("/exit%2F"), "/exit%2F");
+    }
+
+    #[test]
+    fn exit_route_ignores_the_query() {
+        let (port, rx) = serve(OauthConfig::new());
+        assert_eq!(send(port, b"GET /exit?x HTTP/1.1\r\n\r\n"), "");
+        assert_stopped(&rx);
+    }
+
+    #[test]
+    fn encoded_slash_does_not_match_exit_route() {
+        let (port, rx) = serve(OauthConfig::new());
+        let response = send(port, b"GET /exit%2F HTTP/1.1\r\n\r\n");
+        assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(100)),
+            Err(mpsc::RecvTimeoutError::Timeout)
+        );
+        cancel(port).unwrap();
+        assert_stopped(&rx);
+    }
+
+    #[test]
+    fn zero_port_is_tried_after_explicit_ports() {
+        let busy = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let busy_port = busy.local_addr().unwrap().port();
+        let mut config = OauthConfig::new();
+        config.ports = Some(vec![0, busy_port]);
+
+        let reserved = reserve(config).unwrap();
+        assert_eq!(reserved.bind_report().attempted, [busy_port, 0]);
+        assert_ne!(reserved.port(), 0);
+        assert_ne!(reserved.port(), busy_port);
+    }
+
+    #[test]
+    fn server_thread_is_named() {
+        let mut config = OauthConfig::new();
+        config.name = Some("test".to_string());
+        let (tx, rx) = mpsc::channel();
+        let port = start_with_config(config, move |_| {
+            let _ = tx.send(thread::current().name().map(ToString::to_string));
+        })
+        .unwrap();
+        send(
+            port,
+            b"GET /cb HTTP/1.1\r\nFull-Url: http://127.0.0.1/?code=1\r\n\r\n",
+        );
+        assert_eq!(
+            rx.recv_timeout(TIMEOUT).unwrap(),
+            Some(format!("oauth-test-{}", port))
+        );
+    }
+
+    #[test]
+    fn name_with_control_characters_is_rejected() {
+        for name in ["a\0", "a\nb"] {
+            let mut config = OauthConfig::new();
+            config.name = Some(name.to_string());
+            let err = reserve(config).err().unwrap();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn bind_strategy_ephemeral() {
+        let reserved = reserve(OauthConfig::new()).unwrap();
+        assert_eq!(reserved.bind_report().strategy, BindStrategy::Ephemeral);
+        assert_eq!(reserved.bind_report().attempted, [0]);
+
+        let mut config = OauthConfig::new();
+        config.ports = Some(vec![0]);
+        let reserved = reserve(config).unwrap();
+        assert_eq!(reserved.bind_report().strategy, BindStrategy::Ephemeral);
+    }
+
+    #[test]
+    fn bind_strategy_ports() {
+        let free_port = TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut config = OauthConfig::new();
+        config.ports = Some(vec![free_port, 0]);
+
+        let reserved = reserve(config).unwrap();
+        assert_eq!(reserved.bind_report().strategy, BindStrategy::Ports);
+        assert_eq!(reserved.bind_report().attempted, [free_port]);
+        assert_eq!(reserved.port(), free_port);
+    }
+
+    #[test]
+    fn bind_strategy_ephemeral_fallback() {
+        let busy = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let busy_port = busy.local_addr().unwrap().port();
+        let mut config = OauthConfig::new();
+        config.ports = Some(vec![busy_port, 0]);
+
+        let reserved = reserve(config).unwrap();
+        assert_eq!(
+            reserved.bind_report().strategy,
+            BindStrategy::EphemeralFallback
+        );
+    }
+
+    #[test]
+    fn empty_ports_are_rejected() {
+        let mut config = OauthConfig::new();
+        config.ports = Some(Vec::new());
+        let err = reserve(config).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn method_response_status_is_validated() {
+        for status in ["200 OK", "204 No Content", "418 I'm a teapot"] {
+            let over = MethodResponse {
+                status: Some(status.to_string()),
+                ..Default::default()
+            };
+            assert!(over.validate("options_response").is_ok(), "{:?}", status);
+        }
+        for status in ["", "OK", "20 OK", "200", "200 ", "2OO OK", "200 OK\r\nX: y"] {
+            let over = MethodResponse {
+                status: Some(status.to_string()),
+                ..Default::default()
+            };
+            let err = over.validate("options_response").unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{:?}", status);
+        }
+    }
+
+    #[test]
+    fn options_gets_empty_204_by_default() {
+        let (port, rx) = serve(OauthConfig::new());
+        assert_eq!(
+            send(port, b"OPTIONS / HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 204 No Content\r\nAllow: GET, POST, OPTIONS\r\n\r\n"
+        );
+        cancel(port).unwrap();
+        assert_stopped(&rx);
+    }
+
+    #[test]
+    fn head_gets_get_content_length_without_body() {
+        let mut config = OauthConfig::new();
+        config.allowed_methods = Some(vec!["GET".to_string(), "HEAD".to_string()]);
+        let (port, rx) = serve(config);
+
+        let get = send(port, b"GET / HTTP/1.1\r\n\r\n");
+        let body_len = get.split_once("\r\n\r\n").unwrap().1.len();
+        assert_eq!(
+            send(port, b"HEAD / HTTP/1.1\r\n\r\n"),
+            format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", body_len)
+        );
+        cancel(port).unwrap();
+        assert_stopped(&rx);
+    }
+
+    #[test]
+    fn method_response_headers_are_validated() {
+        for name in [
+            "",
+            "X Header",
+            "X:Header",
+            "Content-Length",
+            "transfer-encoding",
+        ] {
+            let over = MethodResponse {
+                headers: Some(vec![(name.to_string(), "1".to_string())]),
+                ..Default::default()
+            };
+            let err = over.validate("options_response").unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn content_length_override_is_rejected() {
+        let mut config = OauthConfig::new();
+        config.head_response = Some(MethodResponse {
+            headers: Some(vec![("Content-Length".to_string(), "0".to_string())]),
+            ..Default::default()
+        });
+        let err = reserve(config).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn options_response_allow_replaces_built_in_allow() {
+        let mut config = OauthConfig::new();
+        config.options_response = Some(MethodResponse {
+            headers: Some(vec![
+                ("allow".to_string(), "GET".to_string()),
+                ("Access-Control-Allow-Origin".to_string(), "*".to_string()),
+            ]),
+            ..Default::default()
+        });
+        let (port, rx) = serve(config);
+        assert_eq!(
+            send(port, b"OPTIONS / HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 204 No Content\r\nallow: GET\r\nAccess-Control-Allow-Origin: *\r\n\r\n"
+        );
+        cancel(port).unwrap();
+        assert_stopped(&rx);
+    }
+
+    #[test]
+    fn options_response_body_is_dropped_for_204() {
+        let mut config = OauthConfig::new();
+        config.options_response = Some(MethodResponse {
+            status: Some("204 No Content".to_string()),
+            body: Some("ignored".to_string()),
+            ..Default::default()
+        });
+        let (port, rx) = serve(config);
+        assert_eq!(
+            send(port, b"OPTIONS / HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 204 No Content\r\nAllow: GET, POST, OPTIONS\r\n\r\n"
+        );
+        cancel(port).unwrap();
+        assert_stopped(&rx);
+    }
+
+    #[test]
+    fn head_response_has_no_content_length_for_304() {
+        let mut config = OauthConfig::new();
+        config.allowed_methods = Some(vec!["GET".to_string(), "HEAD".to_string()]);
+        config.head_response = Some(MethodResponse {
+            status: Some("304 Not Modified".to_string()),
+            ..Default::default()
+        });
+        let (port, rx) = serve(config);
+        assert_eq!(
+            send(port, b"HEAD / HTTP/1.1\r\n\r\n"),
+            "HTTP/1.1 304 Not Modified\r\n\r\n"
+        );
+        cancel(port).unwrap();
+        assert_stopped(&rx);
+    }
+
+    #[test]
+    fn issuer_param_is_read_from_query_or_fragment() {
+        assert_eq!(
+            issuer_param("http://127.0.0.1/?code=1&iss=https%3A%2F%2Fidp.example").as_deref(),
+            Some("https://idp.example")
+        );
+        assert_eq!(
+            issuer_param("http://127.0.0.1/#code=1&iss=https%3A%2F%2Fidp.example").as_deref(),
+            Some("https://idp.example")
+        );
+        assert_eq!(issuer_param("http://127.0.0.1/?code=1"), None);
+        assert_eq!(issuer_param("not a url"), None);
+    }
+
+    fn issuer_config() -> (OauthConfig, Arc<Mutex<Vec<Option<String>>>>) {
+        let invalid = Arc::new(Mutex::new(Vec::new()));
+        let mut config = OauthConfig::new();
+        config.expected_issuer = Some("https://idp.example".to_string());
+        let recorded = invalid.clone();
+        config.on_invalid_issuer = Some(Box::new(move |issuer| {
+            recorded.lock().unwrap().push(issuer);
+        }));
+        (config, invalid)
+    }
+
+    #[test]
+    fn matching_issuer_is_accepted() {
+        let (config, invalid) = issuer_config();
+        assert!(
+            validate_issuer(&config, "http://127.0.0.1/?iss=https%3A%2F%2Fidp.example").is_ok()
+        );
+        assert!(invalid.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn mismatched_issuer_is_rejected() {
+        let (config, invalid) = issuer_config();
+        assert_eq!(
+            validate_issuer(&config, "http://127.0.0.1/?iss=https%3A%2F%2Fevil.example"),
+            Err("Issuer `https://evil.example` doesn't match the expected issuer.".to_string())
+        );
+        assert_eq!(
+            *invalid.lock().unwrap(),
+            [Some("https://evil.example".to_string())]
+        );
+    }
+
+    #[test]
+    fn missing_issuer_is_rejected() {
+        let (config, invalid) = issuer_config();
+        assert_eq!(
+            validate_issuer(&config, "http://127.0.0.1/?code=1"),
+            Err("Missing `iss` parameter.".to_string())
+        );
+        assert_eq!(*invalid.lock().unwrap(), [None]);
+    }
+
+    #[test]
+    fn invalid_issuer_skips_the_handler() {
+        let (mut config, invalid) = issuer_config();
+        config.verbose_rejections = true;
+        let (port, rx) = serve(config);
+
+        let response = send(
+            port,
+            b"GET /cb HTTP/1.1\r\nFull-Url: http://127.0.0.1/?code=1&iss=https%3A%2F%2Fevil.example\r\n\r\n",
+        );
+        assert!(
+            response.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+            "{}",
+            response
+        );
+        assert!(
+            response.ends_with("Issuer `https://evil.example` doesn't match the expected issuer.")
+        );
+        assert_eq!(
+            rx.recv_timeout(Duration::from_millis(100)),
+            Err(mpsc::RecvTimeoutError::Timeout)
+        );
+        assert_eq!(invalid.lock().unwrap().len(), 1);
+
+        // The server keeps waiting for a valid redirect.
+        send(
+            port,
+            b"GET /cb HTTP/1.1\r\nFull-Url: http://127.0.0.1/?code=1&iss=https%3A%2F%2Fidp.example\r\n\r\n",
+        );
+        assert_eq!(
+            rx.recv_timeout(TIMEOUT).unwrap(),
+            "http://127.0.0.1/?code=1&iss=https%3A%2F%2Fidp.example"
+        );
+        assert_stopped(&rx);
+    }
+
+    #[test]
+    fn connection_queued_behind_redirect_gets_shutdown_response() {
+        let reserved = reserve(OauthConfig::new()).unwrap();
+        let port = reserved.port();
+        let mut redirect = TcpStream::connect((Ipv4Addr::LOCALHOST, port)).unwrap();
+        redirect
+            .write_all(b"GET /cb HTTP/1.1\r\nFull-Url: http://127.0.0.1/?code=1\r\n\r\n")
+            .unwrap();
+        let queued = thread::spawn(move || send(port, b"GET / HTTP/1.1\r\n\r\n"));
+        // Give the second connection time to be queued before the server starts accepting.
+        thread::sleep(Duration::from_millis(100));
+
+        let (tx, rx) = mpsc::channel();
+        reserved
+            .accept(move |url| {
+                let _ = tx.send(url);
+            })
+            .unwrap();
+
+        assert_eq!(
+            rx.recv_timeout(TIMEOUT).unwrap(),
+            "http://127.0.0.1/?code=1"
+        );
+        let response = queued.join().unwrap();
+        assert!(
+            response.starts_with("HTTP/1.1 503 Service Unavailable\r\n"),
+            "{}",
+            response
+        );
+        assert!(response
+            .ends_with("<html><body>This login session has ended, please retry.</body></html>"));
+        assert_stopped(&rx);
+    }
+
+    #[test]
+    fn accept_errors_of_one_connection_are_retried() {
+        for kind in [
+            std::io::ErrorKind::ConnectionAborted,
+            std::io::ErrorKind::ConnectionReset,
+            std::io::ErrorKind::Interrupted,
+        ] {
+            assert_eq!(accept_error_action(kind), AcceptAction::Retry, "{:?}", kind);
+        }
+    }
+
+    #[test]
+    fn persistent_accept_errors_back_off() {
+        for kind in [
+            std::io::ErrorKind::Other,
+            std::io::ErrorKind::OutOfMemory,
+            std::io::ErrorKind::PermissionDenied,
+        ] {
+            assert_eq!(
+                accept_error_action(kind),
+                AcceptAction::Backoff,
+                "{:?}",
+                kind
+            );
+        }
+    }
+
+    #[test]
+    fn accept_error_on_closed_socket_stops() {
+        assert_eq!(
+            accept_error_action(std::io::ErrorKind::InvalidInput),
+            AcceptAction::Stop
+        );
+    }
+
+    #[test]
+    fn future_resolves_with_the_captured_url() {
+        let (port, future) = start_as_future(OauthConfig::new()).unwrap();
+        send(
+            port,
+            b"GET /cb HTTP/1.1\r\nFull-Url: http://127.0.0.1/?code=1\r\n\r\n",
+        );
+        assert_eq!(block_on(future).unwrap(), "http://127.0.0.1/?code=1");
+    }
+
+    #[test]
+    fn future_resolves_with_interrupted_on_cancel() {
+        let (port, future) = start_as_future(OauthConfig::new()).unwrap();
+        cancel(port).unwrap();
+        let err = block_on(future).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn empty_connection_gets_no_response() {
+        let (port, rx) = serve(OauthConfig::new());
+        assert_eq!(send(port, b""), "");
+        cancel(port).unwrap();
+        assert_stopped(&rx);
+    }
+
+    #[test]
+    fn incomplete_request_gets_no_response() {
+        let (port, rx) = serve(OauthConfig::new());
+        assert_eq!(send(port, b"GET / HTTP/1.1\r\nHost: localhost"), "");
+        cancel(port).unwrap();
+        assert_stopped(&rx);
+    }
+
+    #[test]
+    fn too_large_request_is_rejected() {
+        let (port, rx) = serve(OauthConfig::new());
+        let mut request = b"GET / HTTP/1.1\r\nX-Padding: ".to_vec();
+        request.resize(MAX_REQUEST_SIZE, b'a');
+        let response = send(port, &request);
+        assert!(
+            response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+            "{}",
+            response
+        );
+        cancel(port).unwrap();
+        assert_stopped(&rx);
+    }
+
+    #[test]
+    fn too_many_headers_are_rejected() {
+        let mut config = OauthConfig::new();
+        config.verbose_rejections = true;
+        let (port, rx) = serve(config);
+        let mut request = "GET / HTTP/1.1\r\n".to_string();
+        for i in 0..17 {
+            request.push_str(&format!("X-Header-{}: x\r\n", i));
+        }
+        request.push_str("\r\n");
+        let response = send(port, request.as_bytes());
+        assert!(
+            response.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"),
+            "{}",
+            response
+        );
+        assert!(response.ends_with("\r\n\r\nRequest contains too many headers."));
+        cancel(port).unwrap();
+        assert_stopped(&rx);
+    }
+
+    #[test]
+    fn malformed_request_is_rejected() {
+        let (port, rx) = serve(OauthConfig::new());
+        let response = send(port, b"GET / HTTP/1.1\r\nInvalid Header\r\n\r\n");
+        assert_eq!(
+            response,
+            "HTTP/1.1 400 Bad Request\r\nContent-Length: 0\r\n\r\n"
+        );
+        cancel(port).unwrap();
+        assert_stopped(&rx);
+    }
+
+    #[cfg(feature = "open")]
+    #[test]
+    fn open_url_accepts_web_and_app_urls() {
+        assert!(validate_open_url("https://example.com/done").is_ok());
+        assert!(validate_open_url("myapp://login/done").is_ok());
+    }
+
+    #[cfg(feature = "open")]
+    #[test]
+    fn open_url_rejects_dangerous_schemes() {
+        for url in [
+            "file:///etc/passwd",
+            "javascript:alert(1)",
+            "data:text/html,<script>alert(1)</script>",
+        ] {
+            let err = validate_open_url(url).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput, "{}", url);
+        }
+    }
+
+    #[cfg(feature = "open")]
+    #[test]
+    fn open_url_rejects_unparsable_urls() {
+        let err = validate_open_url("not a url").unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    }
+
+    #[cfg(feature = "open")]
+    #[test]
+    fn open_url_is_not_deserialized() {
+        let config: OauthConfig =
+            serde_json::from_str(r#"{"on_success_open_url":"ms-msdt:/id"}"#).unwrap();
+        assert!(config.on_success_open_url.is_none());
+    }
+}
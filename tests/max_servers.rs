@@ -0,0 +1,21 @@
+//! Kept in its own test binary, the server count is global to the process.
+
+use tauri_plugin_oauth::{reserve, set_max_servers, OauthConfig, TooManyServers};
+
+#[test]
+fn reserving_more_than_max_servers_fails() {
+    set_max_servers(2);
+    let first = reserve(OauthConfig::new()).unwrap();
+    let _second = reserve(OauthConfig::new()).unwrap();
+
+    let err = reserve(OauthConfig::new()).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::Other);
+    let too_many = err
+        .get_ref()
+        .and_then(|err| err.downcast_ref::<TooManyServers>())
+        .unwrap();
+    assert_eq!(too_many.max, 2);
+
+    drop(first);
+    assert!(reserve(OauthConfig::new()).is_ok());
+}